@@ -1,48 +1,157 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The tunable gains and operating limits for a [`PidController`].
+///
+/// Keeping these in a standalone struct lets them be loaded from a config
+/// file or adjusted live over a control link, independent of the
+/// controller's running state (accumulators, last sample time, ...).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Parameters {
+    pub p_coefficient: f32,
+    pub i_coefficient: f32,
+    pub d_coefficient: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+    pub integral_min: f32,
+    pub integral_max: f32,
+}
+
+/// Returns `(low, high)` with `low <= high`. `f32::clamp` panics if `min >
+/// max`, and `Parameters`' bounds can be swapped by a typo or a malformed
+/// config file, so every place that clamps against them goes through this
+/// first instead of trusting the stored order.
+fn ordered_bounds(min: f32, max: f32) -> (f32, f32) {
+    if min <= max {
+        (min, max)
+    } else {
+        (max, min)
+    }
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Parameters {
+            p_coefficient: 0.0,
+            i_coefficient: 0.0,
+            d_coefficient: 0.0,
+            output_min: f32::NEG_INFINITY,
+            output_max: f32::INFINITY,
+            integral_min: f32::NEG_INFINITY,
+            integral_max: f32::INFINITY,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct PidController {
-    p_coefficient: f32,
-    i_coefficient: f32,
-    d_coefficient: f32,
-    last_runtime: Option<SystemTime>,
+    parameters: Parameters,
+    last_runtime: Option<Instant>,
     last_iterm: f32,
     last_error: f32,
+    last_output: f32,
 }
 
 impl PidController {
     pub fn new(p_coefficient: f32, i_coefficient: f32, d_coefficient: f32) -> PidController {
-        PidController {
+        PidController::from_parameters(Parameters {
             p_coefficient,
             i_coefficient,
             d_coefficient,
+            ..Parameters::default()
+        })
+    }
+
+    /// Builds a controller from a previously-saved or live-tuned [`Parameters`].
+    pub fn from_parameters(parameters: Parameters) -> PidController {
+        PidController {
+            parameters,
             last_runtime: None,
             last_iterm: 0.0,
             last_error: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    pub fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
+
+    /// Replaces the active parameters. When `i_coefficient` changes to a
+    /// non-zero value, the integral accumulator is rescaled by the ratio of
+    /// the old gain to the new one, so the integral contribution to the
+    /// output carries over without a jump.
+    pub fn set_parameters(&mut self, parameters: Parameters) {
+        let old_i = self.parameters.i_coefficient;
+        let new_i = parameters.i_coefficient;
+        if new_i != 0.0 && new_i != old_i {
+            self.last_iterm *= old_i / new_i;
         }
+        self.parameters = parameters;
+    }
+
+    /// Clears accumulated state so the controller behaves as if freshly
+    /// constructed: the integral accumulator and last error are zeroed, and
+    /// the next `update` sees a zero `dt` rather than the time since the
+    /// controller was last disabled. Use this when re-enabling a loop after
+    /// it has been idle or after a setpoint jump.
+    pub fn reset(&mut self) {
+        self.last_iterm = 0.0;
+        self.last_error = 0.0;
+        self.last_runtime = None;
+        self.last_output = 0.0;
+    }
+
+    /// Sets the range the summed output is clamped to. Defaults to `(-inf, inf)`.
+    pub fn with_output_limits(mut self, output_min: f32, output_max: f32) -> PidController {
+        self.parameters.output_min = output_min;
+        self.parameters.output_max = output_max;
+        self
+    }
+
+    /// Sets the range the integral accumulator is clamped to. Defaults to `(-inf, inf)`.
+    pub fn with_integral_limits(mut self, integral_min: f32, integral_max: f32) -> PidController {
+        self.parameters.integral_min = integral_min;
+        self.parameters.integral_max = integral_max;
+        self
     }
 
     pub fn update(&mut self, setpoint: f32, measurement: f32) -> f32 {
         let dt = self.get_time_difference();
+        self.update_with_dt(setpoint, measurement, dt)
+    }
+
+    /// Drives the controller with an explicit time delta instead of sampling the
+    /// clock, so it can be run from a fixed-rate scheduler, replay recorded data,
+    /// or be unit-tested with exact deltas.
+    pub fn update_with_dt(&mut self, setpoint: f32, measurement: f32, dt: Duration) -> f32 {
+        let dt = dt.as_secs_f32();
         let error = self.error(setpoint, measurement);
-        self.p_term(error) * self.p_coefficient
-            + self.i_term(error, dt) * self.i_coefficient
-            + self.d_term(error, dt) * self.d_coefficient
+        let output = self.p_term(error) * self.parameters.p_coefficient
+            + self.i_term(error, dt) * self.parameters.i_coefficient
+            + self.d_term(error, dt) * self.parameters.d_coefficient;
+        let (output_min, output_max) = ordered_bounds(self.parameters.output_min, self.parameters.output_max);
+        let output = output.clamp(output_min, output_max);
+        self.last_output = output;
+        output
     }
 
     fn set_last_runtime(&mut self) {
-        self.last_runtime = Some(SystemTime::now());
+        self.last_runtime = Some(Instant::now());
     }
 
-    fn get_time_difference(&mut self) -> f32 {
-        let now = SystemTime::now();
+    fn get_time_difference(&mut self) -> Duration {
+        let now = Instant::now();
         let dt = if let Some(last) = self.last_runtime {
-            now.duration_since(last).unwrap()
+            now.duration_since(last)
         } else {
             Duration::new(0, 0)
         };
         self.set_last_runtime();
-        dt.as_secs_f32()
+        dt
     }
 
     fn error(&self, setpoint: f32, measurement: f32) -> f32 {
@@ -54,16 +163,29 @@ impl PidController {
     }
 
     fn i_term(&mut self, error: f32, delta_time: f32) -> f32 {
-        let i_term = self.last_iterm + error * delta_time;
-        self.last_iterm = i_term;
-        self.i_coefficient * i_term
+        // Conditional integration: only accumulate while the previous output
+        // was strictly within its limits, so the integrator stops winding up
+        // once the plant can no longer act on it.
+        let (output_min, output_max) = ordered_bounds(self.parameters.output_min, self.parameters.output_max);
+        let output_unsaturated = self.last_output > output_min && self.last_output < output_max;
+        if output_unsaturated {
+            self.last_iterm += error * delta_time;
+        }
+        let (integral_min, integral_max) =
+            ordered_bounds(self.parameters.integral_min, self.parameters.integral_max);
+        self.last_iterm = self.last_iterm.clamp(integral_min, integral_max);
+        // Accumulated error*dt, with no gain applied: i_coefficient is
+        // applied once by the caller at summation time, and retuning it via
+        // `set_parameters` rescales this accumulator so the output does not
+        // jump.
+        self.last_iterm
     }
 
     fn d_term(&mut self, error: f32, delta_time: f32) -> f32 {
         if delta_time == 0.0_f32 {
             return 0.0_f32;
         }
-            
+
         let d_term = (error - self.last_error) / delta_time;
         self.last_error = error;
         d_term
@@ -92,16 +214,65 @@ mod tests {
         assert_eq!(
             PidController::new(TestPid::P, TestPid::I, TestPid::D),
             PidController {
-                p_coefficient: TestPid::P,
-                i_coefficient: TestPid::I,
-                d_coefficient: TestPid::D,
+                parameters: Parameters {
+                    p_coefficient: TestPid::P,
+                    i_coefficient: TestPid::I,
+                    d_coefficient: TestPid::D,
+                    ..Parameters::default()
+                },
                 last_runtime: None,
                 last_iterm: TestPid::ZERO,
                 last_error: TestPid::ZERO,
+                last_output: TestPid::ZERO,
             }
         );
     }
 
+    #[test]
+    fn from_parameters_round_trips_through_the_accessor() {
+        let parameters = Parameters {
+            p_coefficient: TestPid::P,
+            i_coefficient: TestPid::I,
+            d_coefficient: TestPid::D,
+            output_min: -5.0,
+            output_max: 5.0,
+            integral_min: -2.0,
+            integral_max: 2.0,
+        };
+        let pid = PidController::from_parameters(parameters.clone());
+        assert_eq!(pid.parameters(), &parameters);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn parameters_round_trip_through_json() {
+        let parameters = Parameters {
+            p_coefficient: TestPid::P,
+            i_coefficient: TestPid::I,
+            d_coefficient: TestPid::D,
+            output_min: -5.0,
+            output_max: 5.0,
+            integral_min: -2.0,
+            integral_max: 2.0,
+        };
+
+        let json = serde_json::to_string(&parameters).unwrap();
+        let deserialized: Parameters = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(deserialized, parameters);
+    }
+
+    #[test]
+    fn set_parameters_replaces_the_active_gains() {
+        let mut pid = PidController::new(TestPid::P, TestPid::ZERO, TestPid::ZERO);
+        let new_parameters = Parameters {
+            p_coefficient: 2.0,
+            ..Parameters::default()
+        };
+        pid.set_parameters(new_parameters.clone());
+        assert_eq!(pid.parameters(), &new_parameters);
+    }
+
     #[test]
     fn can_calculate_error_zero() {
         let setpoint = 24.7_f32;
@@ -200,11 +371,143 @@ mod tests {
     fn can_detect_differences_in_time() {
         let mut pid = PidController::new(TestPid::P, TestPid::I, TestPid::D);
         // start time zero following first call to this function
-        assert_eq!(pid.get_time_difference(), 0.0_f32);
+        assert_eq!(pid.get_time_difference(), Duration::new(0, 0));
         // Sleep for some time to have something worth measuring
         let sleep = Duration::from_millis(1000);
         thread::sleep(sleep);
         // Make sure the measured time difference is larger than the time we slept.
-        assert!(pid.get_time_difference() > sleep.as_secs_f32());
+        assert!(pid.get_time_difference() > sleep);
+    }
+
+    #[test]
+    fn integral_halts_once_output_is_saturated() {
+        let mut pid =
+            PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO).with_output_limits(-10.0, 10.0);
+        pid.last_output = 10.0; // already pinned to the upper limit
+        let iterm = pid.i_term(5.0, 1.0);
+        assert_eq!(iterm, TestPid::ZERO);
+        assert_eq!(pid.last_iterm, TestPid::ZERO);
+    }
+
+    #[test]
+    fn integral_resumes_once_output_is_back_inside_limits() {
+        let mut pid =
+            PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO).with_output_limits(-10.0, 10.0);
+        pid.last_output = 0.0; // comfortably inside the limits
+        let iterm = pid.i_term(5.0, 1.0);
+        assert_eq!(iterm, 5.0);
+    }
+
+    #[test]
+    fn integral_accumulator_is_clamped_to_integral_limits() {
+        let mut pid =
+            PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO).with_integral_limits(-2.0, 2.0);
+        let iterm = pid.i_term(5.0, 1.0);
+        assert_eq!(iterm, 2.0);
+        assert_eq!(pid.last_iterm, 2.0);
+    }
+
+    #[test]
+    fn update_output_is_clamped_to_output_limits() {
+        let mut pid =
+            PidController::new(TestPid::P, TestPid::ZERO, TestPid::ZERO).with_output_limits(-5.0, 5.0);
+        let output = pid.update(100.0, 0.0);
+        assert_eq!(output, 5.0);
+        assert_eq!(pid.last_output, 5.0);
+    }
+
+    #[test]
+    fn inverted_output_limits_do_not_panic_and_clamp_as_if_sorted() {
+        let mut pid =
+            PidController::new(TestPid::P, TestPid::ZERO, TestPid::ZERO).with_output_limits(5.0, -5.0);
+        let output = pid.update(100.0, 0.0);
+        assert_eq!(output, 5.0);
+        assert_eq!(pid.last_output, 5.0);
+    }
+
+    #[test]
+    fn inverted_integral_limits_do_not_panic_and_clamp_as_if_sorted() {
+        let mut pid =
+            PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO).with_integral_limits(2.0, -2.0);
+        let iterm = pid.i_term(5.0, 1.0);
+        assert_eq!(iterm, 2.0);
+        assert_eq!(pid.last_iterm, 2.0);
+    }
+
+    #[test]
+    fn update_with_dt_is_deterministic_and_does_not_touch_the_clock() {
+        let setpoint = 55.6_f32;
+        let measurement = TestPid::ZERO;
+        let error = setpoint - measurement;
+        let dt = Duration::from_millis(500);
+
+        let mut pid = PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO);
+        let first = pid.update_with_dt(setpoint, measurement, dt);
+        let second = pid.update_with_dt(setpoint, measurement, dt);
+
+        assert_eq!(first, error * dt.as_secs_f32());
+        assert_eq!(second, first + error * dt.as_secs_f32());
+    }
+
+    #[test]
+    fn update_delegates_to_update_with_dt() {
+        let mut clocked = PidController::new(TestPid::P, TestPid::ZERO, TestPid::ZERO);
+        let mut explicit = PidController::new(TestPid::P, TestPid::ZERO, TestPid::ZERO);
+
+        assert_eq!(
+            clocked.update(10.0, 4.0),
+            explicit.update_with_dt(10.0, 4.0, Duration::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn reset_clears_accumulated_state() {
+        let mut pid = PidController::new(TestPid::P, TestPid::I, TestPid::D);
+        pid.update_with_dt(10.0, 0.0, Duration::from_millis(100));
+        pid.update_with_dt(10.0, 0.0, Duration::from_millis(100));
+
+        pid.reset();
+
+        assert_eq!(pid.last_iterm, TestPid::ZERO);
+        assert_eq!(pid.last_error, TestPid::ZERO);
+        assert!(pid.last_runtime.is_none());
+        assert_eq!(pid.last_output, TestPid::ZERO);
+        assert_eq!(pid.get_time_difference(), Duration::new(0, 0));
+    }
+
+    #[test]
+    fn reset_clears_saturation_so_the_integral_resumes_accumulating_on_the_next_tick() {
+        let mut pid =
+            PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO).with_output_limits(-10.0, 10.0);
+        // Drive the output to saturation at output_max.
+        pid.update_with_dt(20.0, 0.0, Duration::from_millis(1000));
+        assert_eq!(pid.last_output, 10.0);
+
+        pid.reset();
+
+        // A fresh controller's anti-windup gate must not inherit the
+        // pre-reset saturated output, or the integral would wrongly stay
+        // frozen on the very next tick.
+        pid.update_with_dt(-5.0, 0.0, Duration::from_millis(1000));
+        assert_eq!(pid.last_iterm, -5.0);
+    }
+
+    #[test]
+    fn retuning_i_coefficient_rescales_the_existing_integral_instead_of_jumping() {
+        let mut pid = PidController::new(TestPid::ZERO, TestPid::I, TestPid::ZERO);
+        pid.i_term(5.0, 1.0);
+        assert_eq!(pid.last_iterm, 5.0);
+
+        pid.set_parameters(Parameters {
+            i_coefficient: 2.0,
+            ..pid.parameters().clone()
+        });
+
+        // The accumulator is rescaled by old/new gain (1.0 / 2.0)...
+        assert_eq!(pid.last_iterm, 2.5);
+        // ...so the next summation under the new gain gives the same integral
+        // contribution as before (1.0 * 5.0), rather than a jump to 2.0 * 5.0.
+        let output = pid.update_with_dt(5.0, 0.0, Duration::new(0, 0));
+        assert_eq!(output, 2.0 * pid.last_iterm);
     }
 }